@@ -1,24 +1,31 @@
 #[macro_use]
 extern crate log;
 
+use std::collections::HashMap;
 use std::io::Write;
-use std::io::{self, BufRead, Read, Result};
-use std::path::Path;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use std::{env, panic};
 
 use env_logger::{Builder, Env};
+use futures::future::join_all;
+use futures::Future;
 use indexmap::map::IndexMap;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use librespot_audio::{AudioDecrypt, AudioFile};
+use librespot_core::audio_key::AudioKey;
 use librespot_core::authentication::Credentials;
+use librespot_core::cache::Cache;
 use librespot_core::config::SessionConfig;
 use librespot_core::session::Session;
 use librespot_core::spotify_id::{FileId, SpotifyId};
 use librespot_metadata::{Album, Artist, Episode, FileFormat, Metadata, Playlist, Show, Track};
 use regex::Regex;
 use scoped_threadpool::Pool;
+use serde::Deserialize;
 use tokio_core::reactor::Core;
 
 enum IndexedTy {
@@ -28,35 +35,508 @@ enum IndexedTy {
 
 use IndexedTy::*;
 
-fn get_usable_file_id(files: &linear_map::LinearMap<FileFormat, FileId>) -> &FileId {
-    files
-        .get(&FileFormat::OGG_VORBIS_320)
-        .or_else(|| files.get(&FileFormat::OGG_VORBIS_160))
-        .or_else(|| files.get(&FileFormat::OGG_VORBIS_96))
-        .expect("Could not find a OGG_VORBIS format for the track.")
+// Options read from the optional TOML config file. Every field is optional so a
+// partial file is valid; CLI flags and positional arguments take precedence.
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    username: Option<String>,
+    password: Option<String>,
+    quality: Option<String>,
+    output_dir: Option<String>,
+    template: Option<String>,
+}
+
+// Load the config from `<config_dir>/oggify/config.toml`, returning the default
+// (all-`None`) config when no file is present. Parse errors warn and fall back.
+fn load_config() -> Config {
+    let path = match dirs::config_dir() {
+        Some(dir) => dir.join("oggify").join("config.toml"),
+        None => return Config::default(),
+    };
+    if !path.exists() {
+        return Config::default();
+    }
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Could not parse config {}: {}", path.display(), e);
+            Config::default()
+        }),
+        Err(e) => {
+            warn!("Could not read config {}: {}", path.display(), e);
+            Config::default()
+        }
+    }
+}
+
+// Quality presets map a user-facing choice to an ordered list of formats to
+// try, most preferred first. `get_usable_file_id` walks the list and picks the
+// first format the track actually offers.
+#[derive(Clone, Copy)]
+enum QualityPreset {
+    OggOnly,
+    Mp3Only,
+    BestBitrate,
+}
+
+impl QualityPreset {
+    fn from_arg(value: &str) -> std::result::Result<QualityPreset, String> {
+        match value.to_ascii_lowercase().as_str() {
+            "ogg" => Ok(QualityPreset::OggOnly),
+            "mp3" => Ok(QualityPreset::Mp3Only),
+            "best" => Ok(QualityPreset::BestBitrate),
+            other => Err(format!(
+                "Unknown quality preset '{}' (expected ogg, mp3 or best)",
+                other
+            )),
+        }
+    }
+
+    fn formats(&self) -> &'static [FileFormat] {
+        match self {
+            QualityPreset::OggOnly => &[
+                FileFormat::OGG_VORBIS_320,
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::OGG_VORBIS_96,
+            ],
+            QualityPreset::Mp3Only => &[
+                FileFormat::MP3_320,
+                FileFormat::MP3_256,
+                FileFormat::MP3_160,
+                FileFormat::MP3_96,
+            ],
+            QualityPreset::BestBitrate => &[
+                FileFormat::OGG_VORBIS_320,
+                FileFormat::MP3_320,
+                FileFormat::MP3_256,
+                FileFormat::OGG_VORBIS_160,
+                FileFormat::MP3_160,
+                FileFormat::OGG_VORBIS_96,
+                FileFormat::MP3_96,
+            ],
+        }
+    }
+}
+
+// The bitrate handed to `AudioFile::open`, derived from the chosen format.
+fn format_bitrate(format: FileFormat) -> u16 {
+    match format {
+        FileFormat::OGG_VORBIS_320 | FileFormat::MP3_320 => 320,
+        FileFormat::MP3_256 => 256,
+        FileFormat::OGG_VORBIS_160 | FileFormat::MP3_160 => 160,
+        _ => 96,
+    }
+}
+
+// The output file extension matching the chosen format's codec.
+fn format_extension(format: FileFormat) -> &'static str {
+    match format {
+        FileFormat::MP3_320 | FileFormat::MP3_256 | FileFormat::MP3_160 | FileFormat::MP3_96 => {
+            "mp3"
+        }
+        _ => "ogg",
+    }
+}
+
+// Pull a `--name value` pair out of the argument list, returning the value and
+// removing both tokens so the remaining entries stay positional.
+fn take_flag(args: &mut Vec<String>, names: &[&str]) -> Option<String> {
+    for name in names {
+        if let Some(pos) = args.iter().position(|a| a == name) {
+            if pos + 1 < args.len() {
+                let value = args.remove(pos + 1);
+                args.remove(pos);
+                return Some(value);
+            }
+        }
+    }
+    None
+}
+
+// Pull a `--name` switch out of the argument list, returning whether it was
+// present and removing it so the remaining entries stay positional.
+fn take_switch(args: &mut Vec<String>, name: &str) -> bool {
+    if let Some(pos) = args.iter().position(|a| a == name) {
+        args.remove(pos);
+        true
+    } else {
+        false
+    }
+}
+
+// Fetch the raw cover-art bytes for an album cover `FileId` from Spotify's
+// image CDN. Tagging is best-effort, so failures only warn.
+fn fetch_cover(file_id: &FileId) -> Option<Vec<u8>> {
+    let url = format!("https://i.scdn.co/image/{}", file_id.to_base16());
+    match reqwest::blocking::get(&url).and_then(|resp| resp.bytes()) {
+        Ok(bytes) => Some(bytes.to_vec()),
+        Err(e) => {
+            warn!("Could not fetch cover art: {}", e);
+            None
+        }
+    }
+}
+
+// Write Vorbis comments / ID3 tags and the front-cover picture onto an
+// already-written track file. The tag type is inferred from the file itself,
+// so the same code path serves both OGG and MP3 output.
+fn embed_tags(path: &Path, tags: &TrackTags, cover: Option<Vec<u8>>) -> lofty::error::Result<()> {
+    use lofty::{
+        Accessor, ItemKey, ItemValue, Picture, PictureType, Probe, Tag, TagExt, TagItem,
+        TaggedFileExt,
+    };
+
+    let mut tagged_file = Probe::open(path)?.read()?;
+    let tag = match tagged_file.primary_tag_mut() {
+        Some(tag) => tag,
+        None => {
+            let tag_type = tagged_file.primary_tag_type();
+            tagged_file.insert_tag(Tag::new(tag_type));
+            tagged_file.primary_tag_mut().unwrap()
+        }
+    };
+
+    tag.set_title(tags.title.clone());
+    tag.set_album(tags.album.clone());
+    tag.insert_text(ItemKey::AlbumArtist, tags.album_artist.clone());
+    // Store each credited artist as its own value rather than a joined string.
+    tag.remove_key(&ItemKey::TrackArtist);
+    for artist in &tags.artists {
+        tag.push(TagItem::new(
+            ItemKey::TrackArtist,
+            ItemValue::Text(artist.clone()),
+        ));
+    }
+
+    if tags.track_number > 0 {
+        tag.set_track(tags.track_number);
+    }
+    if tags.disc_number > 0 {
+        tag.set_disk(tags.disc_number);
+    }
+    if !tags.date.is_empty() {
+        // Store the full release date plus the year for players that only read
+        // one or the other.
+        tag.insert_text(ItemKey::RecordingDate, tags.date.clone());
+        if let Ok(year) = tags.date[..tags.date.len().min(4)].parse() {
+            tag.set_year(year);
+        }
+    }
+
+    if let Some(bytes) = cover {
+        // Let lofty sniff the MIME type from the bytes instead of assuming JPEG;
+        // Spotify serves JPEG today but nothing guarantees it stays that way.
+        match Picture::from_reader(&mut &bytes[..]) {
+            Ok(mut picture) => {
+                picture.set_pic_type(PictureType::CoverFront);
+                tag.push_picture(picture);
+            }
+            Err(e) => warn!("Could not parse cover art: {}", e),
+        }
+    }
+
+    tag.save_to_path(path)
+}
+
+// Expand a filename template such as `{artist}/{album}/{track_number:02} - {title}`
+// against the resolved metadata fields. A missing field expands to the empty
+// string; a `:0N` suffix zero-pads a numeric field to N digits. Field values are
+// sanitised as they are substituted, so only path separators written literally in
+// the template survive to express nested directories.
+fn expand_template(template: &str, fields: &HashMap<&str, String>) -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            out.push(c);
+            continue;
+        }
+        let mut token = String::new();
+        for tc in chars.by_ref() {
+            if tc == '}' {
+                break;
+            }
+            token.push(tc);
+        }
+        let (name, spec) = match token.split_once(':') {
+            Some((name, spec)) => (name, Some(spec)),
+            None => (token.as_str(), None),
+        };
+        // Sanitise the field value before it lands in the template so a `/` in a
+        // title or artist (e.g. "AC/DC") becomes part of the name rather than a
+        // stray directory. Only literal separators in the template itself split
+        // components in `build_output_path`.
+        let value = sanitize_filename::sanitize(fields.get(name).cloned().unwrap_or_default());
+        match spec {
+            Some(spec) if spec.starts_with('0') => match (spec[1..].parse(), value.parse::<i64>()) {
+                (Ok(width), Ok(num)) => out.push_str(&format!("{:0width$}", num, width = width)),
+                _ => out.push_str(&value),
+            },
+            _ => out.push_str(&value),
+        }
+    }
+    out
+}
+
+// Turn an expanded template into a concrete path under `output_dir`, sanitising
+// each component individually and appending the codec extension.
+fn build_output_path(output_dir: &Path, expanded: &str, extension: &str) -> PathBuf {
+    let mut path = output_dir.to_path_buf();
+    let components: Vec<&str> = expanded.split('/').filter(|c| !c.is_empty()).collect();
+    for (index, component) in components.iter().enumerate() {
+        let sanitized = sanitize_filename::sanitize(component);
+        // Append the extension to the final component rather than using
+        // `set_extension`, which would truncate at any `.` in the title.
+        if index + 1 == components.len() {
+            path.push(format!("{}.{}", sanitized, extension));
+        } else {
+            path.push(sanitized);
+        }
+    }
+    path
+}
+
+// Drive a batch of identical futures on the reactor while keeping at most
+// `limit` of them in flight at a time. This lets `--jobs` bound the number of
+// concurrent audio-key and stream-open round-trips instead of opening every
+// stream at once. Results are returned in the original order.
+fn run_bounded<F>(core: &mut Core, futures: Vec<F>, limit: usize) -> Result<Vec<F::Item>, F::Error>
+where
+    F: Future,
+{
+    let mut out = Vec::with_capacity(futures.len());
+    let mut iter = futures.into_iter();
+    loop {
+        let chunk: Vec<F> = iter.by_ref().take(limit.max(1)).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        out.extend(core.run(join_all(chunk))?);
+    }
+    Ok(out)
+}
+
+// Drop any query string or fragment (e.g. the `?si=...` share token) from a
+// pasted line so it doesn't leak into the id capture.
+fn strip_query(line: &str) -> &str {
+    line.split(|c| c == '?' || c == '#').next().unwrap_or(line)
+}
+
+// Follow the HTTP redirect behind a shortened link, returning the canonical URL
+// reqwest lands on. A missing scheme is assumed to be HTTPS.
+fn resolve_redirect(url: &str) -> Option<String> {
+    let url = if url.starts_with("http") {
+        url.to_string()
+    } else {
+        format!("https://{}", url)
+    };
+    match reqwest::blocking::get(&url) {
+        Ok(resp) => Some(resp.url().to_string()),
+        Err(e) => {
+            warn!("Could not resolve shortened link {}: {}", url, e);
+            None
+        }
+    }
+}
+
+// Normalise a pasted line into something the URI regex can match: strip the
+// query/fragment and resolve `spotify.link` short links to their destination.
+fn canonicalize_line(line: &str) -> String {
+    let trimmed = strip_query(line);
+    if trimmed.contains("spotify.link") || trimmed.contains("spotify.app.link") {
+        if let Some(resolved) = resolve_redirect(trimmed) {
+            return strip_query(&resolved).to_string();
+        }
+    }
+    trimmed.to_string()
+}
+
+fn get_usable_file_id<'a>(
+    files: &'a linear_map::LinearMap<FileFormat, FileId>,
+    formats: &[FileFormat],
+) -> (FileFormat, &'a FileId) {
+    formats
+        .iter()
+        .find_map(|format| files.get(format).map(|id| (*format, id)))
+        .expect("Could not find a usable format for the track.")
+}
+
+// The tags to apply to a downloaded track once it has been written.
+struct TrackTags {
+    title: String,
+    artists: Vec<String>,
+    album: String,
+    album_artist: String,
+    track_number: u32,
+    disc_number: u32,
+    date: String,
+    cover: Option<FileId>,
+}
+
+// What to do with the decrypted bytes once a download finishes. Resolved up
+// front (on the reactor thread) so the worker threads never touch the session.
+enum Finish {
+    Write {
+        fname: String,
+        tags: Option<TrackTags>,
+    },
+    Helper {
+        program: String,
+        args: Vec<String>,
+    },
+}
+
+// A fully-resolved download, ready to be streamed and decrypted on a worker.
+struct Job {
+    label: String,
+    encrypted_file: AudioFile,
+    key: AudioKey,
+    size: usize,
+    // Bytes to skip at the start of the decrypted stream. The 167-byte Spotify
+    // header only prefixes OGG Vorbis streams; MP3 streams carry no such header.
+    header_offset: usize,
+    finish: Finish,
+}
+
+// Stream, decrypt and dispose of a single download on a worker thread,
+// advancing `bar` by bytes read. The reactor is pumped by the main thread.
+fn run_job(job: Job, bar: ProgressBar) {
+    let Job {
+        label,
+        mut encrypted_file,
+        key,
+        size,
+        header_offset,
+        finish,
+    } = job;
+
+    bar.set_length(size as u64);
+    let mut buffer = Vec::with_capacity(size);
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        match encrypted_file.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk[..n]);
+                bar.set_position(buffer.len() as u64);
+            }
+            Err(e) => {
+                bar.abandon_with_message(format!("{}: read error: {}", label, e));
+                return;
+            }
+        }
+    }
+
+    let mut decrypted_buffer = Vec::new();
+    if let Err(e) = AudioDecrypt::new(key, &buffer[..]).read_to_end(&mut decrypted_buffer) {
+        bar.abandon_with_message(format!("{}: decrypt error: {}", label, e));
+        return;
+    }
+    let payload = &decrypted_buffer[header_offset..];
+
+    match finish {
+        Finish::Write { fname, tags } => {
+            if let Err(e) = std::fs::write(&fname, payload) {
+                bar.abandon_with_message(format!("{}: write error: {}", label, e));
+                return;
+            }
+            if let Some(tags) = tags {
+                let cover = tags.cover.as_ref().and_then(fetch_cover);
+                if let Err(e) = embed_tags(Path::new(&fname), &tags, cover) {
+                    warn!("Could not write tags for {}: {}", fname, e);
+                }
+            }
+        }
+        Finish::Helper { program, args } => {
+            let mut cmd = Command::new(program);
+            cmd.stdin(Stdio::piped());
+            cmd.args(args);
+            let mut child = cmd.spawn().expect("Could not run helper program");
+            let pipe = child.stdin.as_mut().expect("Could not open helper stdin");
+            pipe.write_all(payload).expect("Failed to write to stdin");
+            assert!(
+                child
+                    .wait()
+                    .expect("Out of ideas for error messages")
+                    .success(),
+                "Helper script returned an error"
+            );
+        }
+    }
+
+    bar.finish_with_message(format!("{} done", label));
 }
 
 fn main() {
     Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let args: Vec<_> = env::args().collect();
-    assert!(
-        args.len() == 3 || args.len() == 4,
-        "Usage: {} user password [helper_script] < tracks_file",
-        args[0]
-    );
+    let mut args: Vec<String> = env::args().collect();
+    let config = load_config();
+    let quality = match take_flag(&mut args, &["--quality", "--format"]).or(config.quality) {
+        Some(value) => match QualityPreset::from_arg(&value) {
+            Ok(quality) => quality,
+            Err(message) => {
+                error!("{}", message);
+                return;
+            }
+        },
+        None => QualityPreset::OggOnly,
+    };
+    let no_tag = take_switch(&mut args, "--no-tag");
+    let template = take_flag(&mut args, &["--template"]).or(config.template);
+    let output_dir = take_flag(&mut args, &["--output-dir"])
+        .or(config.output_dir)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let jobs = take_flag(&mut args, &["--jobs"])
+        .and_then(|value| value.parse::<usize>().ok())
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .max(1);
+
+    // When credentials come from the config or the auth cache, the positional
+    // arguments collapse to an optional helper script. Otherwise the legacy
+    // `user password [helper_script]` layout applies, with those values
+    // overriding anything from the config.
+    let (username, password, helper) = if args.len() >= 3 {
+        (
+            Some(args[1].clone()),
+            Some(args[2].clone()),
+            args.get(3).cloned(),
+        )
+    } else {
+        (config.username, config.password, args.get(1).cloned())
+    };
 
     let mut core = Core::new().unwrap();
     let handle = core.handle();
     let session_config = SessionConfig::default();
-    let credentials = Credentials::with_password(args[1].to_owned(), args[2].to_owned());
+
+    // Reuse the stored auth blob across runs so the password is only sent once.
+    let cache = dirs::cache_dir().map(|dir| Cache::new(dir.join("oggify"), false));
+    let cached = cache.as_ref().and_then(|c| c.credentials());
+    let credentials = match (username, password) {
+        // A cached blob for the same user stands in for the password.
+        (Some(user), Some(pass)) => match cached {
+            Some(blob) if blob.username == user => blob,
+            _ => Credentials::with_password(user, pass),
+        },
+        _ => cached.expect(
+            "No credentials: supply `user password` on the command line or in config.toml",
+        ),
+    };
+
     info!("Connecting ...");
     let session = core
-        .run(Session::connect(session_config, credentials, None, handle))
+        .run(Session::connect(
+            session_config,
+            credentials,
+            cache.clone(),
+            handle,
+        ))
         .unwrap();
     info!("Connected!");
 
-    let mut threadpool = Pool::new(1);
+    let mut threadpool = Pool::new(jobs as u32);
 
     let re = Regex::new(r"(playlist|track|album|episode|show)[/:]([a-zA-Z0-9]+)").unwrap();
 
@@ -70,10 +550,16 @@ fn main() {
                 if line == "done" {
                     break;
                 }
-                let spotify_captures = re.captures(line);
-                let spotify_match = match spotify_captures {
-                    None => continue,
+                if line.is_empty() {
+                    continue;
+                }
+                let normalized = canonicalize_line(line);
+                let spotify_match = match re.captures(&normalized) {
                     Some(x) => x,
+                    None => {
+                        warn!("Could not parse a Spotify link from line: {}", line);
+                        continue;
+                    }
                 };
                 let spotify_type = spotify_match.get(1).unwrap().as_str();
                 let spotify_id =
@@ -113,199 +599,519 @@ fn main() {
         }
     }
 
-    for (id, value) in ids {
+    // Partition the requested ids by type while preserving insertion order.
+    let mut track_ids = Vec::new();
+    let mut episode_ids = Vec::new();
+    for (id, value) in &ids {
         match value {
-            Track => {
-                let fmtid = id.to_base62();
-                info!("Getting track {}...", id.to_base62());
-                if let Ok(mut track) = core.run(Track::get(&session, id)) {
-                    if !track.available {
-                        warn!("Track {} is not available, finding alternative...", fmtid);
-                        let alt_track = track
-                            .alternatives
-                            .iter()
-                            .map(|id| {
-                                core.run(Track::get(&session, *id))
-                                    .expect("Cannot get track metadata")
-                            })
-                            .find(|alt_track| alt_track.available);
-                        track = match alt_track {
-                            Some(x) => {
-                                warn!("Found track alternative {} -> {}", fmtid, x.id.to_base62());
-                                x
-                            }
-                            None => {
-                                panic!("Could not find alternative for track {}", fmtid);
-                            }
-                        };
-                    }
-                    let artists_strs: Vec<_> = track
-                        .artists
-                        .iter()
-                        .map(|id| {
-                            core.run(Artist::get(&session, *id))
-                                .expect("Cannot get artist metadata")
-                                .name
-                        })
-                        .collect();
-                    debug!(
-                        "File formats: {}",
-                        track
-                            .files
-                            .keys()
-                            .map(|filetype| format!("{:?}", filetype))
-                            .collect::<Vec<_>>()
-                            .join(" ")
-                    );
-                    let file_id = get_usable_file_id(&track.files);
-                    let key = core
-                        .run(session.audio_key().request(track.id, *file_id))
-                        .expect("Cannot get audio key");
-                    let mut encrypted_file = core
-                        .run(AudioFile::open(&session, *file_id, 320, true))
-                        .unwrap();
-                    let mut buffer = Vec::new();
-                    let mut read_all: Result<usize> = Ok(0);
-                    let fname = sanitize_filename::sanitize(format!(
-                        "{} - {}.ogg",
-                        artists_strs.join(", "),
-                        track.name
-                    ));
-
-                    if Path::new(&fname).exists() {
-                        info!("File {} already exists.", fname);
-                    } else {
-                        let fetched = AtomicBool::new(false);
-                        threadpool.scoped(|scope| {
-                            scope.execute(|| {
-                                read_all = encrypted_file.read_to_end(&mut buffer);
-                                fetched.store(true, Ordering::Release);
-                            });
-                            while !fetched.load(Ordering::Acquire) {
-                                core.turn(Some(Duration::from_millis(100)));
-                            }
-                        });
-                        read_all.expect("Cannot read file stream");
-                        let mut decrypted_buffer = Vec::new();
-                        AudioDecrypt::new(key, &buffer[..])
-                            .read_to_end(&mut decrypted_buffer)
-                            .expect("Cannot decrypt stream");
-                        if args.len() == 3 {
-                            let fname = sanitize_filename::sanitize(format!(
-                                "{} - {}.ogg",
-                                artists_strs.join(", "),
-                                track.name
-                            ));
-                            if Path::new(&fname).exists() {
-                                info!("File {} already exists.", fname);
-                            } else {
-                                std::fs::write(&fname, &decrypted_buffer[0xa7..])
-                                    .expect("Cannot write decrypted track");
-                                info!("Filename: {}", fname);
-                            }
-                        } else {
-                            let album = core
-                                .run(Album::get(&session, track.album))
-                                .expect("Cannot get album metadata");
-                            let mut cmd = Command::new(args[3].to_owned());
-                            cmd.stdin(Stdio::piped());
-                            cmd.arg(id.to_base62())
-                                .arg(track.name)
-                                .arg(album.name)
-                                .args(artists_strs.iter());
-                            let mut child = cmd.spawn().expect("Could not run helper program");
-                            let pipe = child.stdin.as_mut().expect("Could not open helper stdin");
-                            pipe.write_all(&decrypted_buffer[0xa7..])
-                                .expect("Failed to write to stdin");
-                            assert!(
-                                child
-                                    .wait()
-                                    .expect("Out of ideas for error messages")
-                                    .success(),
-                                "Helper script returned an error"
-                            );
-                        }
-                    }
+            Track => track_ids.push(*id),
+            Episode => episode_ids.push(*id),
+        }
+    }
+
+    // Resolve all the top-level metadata and open all the streams in batched
+    // round-trips driven together on the reactor, rather than one id at a time,
+    // so the network latency overlaps instead of serialising. The byte
+    // transfers themselves happen afterwards on the worker pool.
+    let mut job_list: Vec<Job> = Vec::new();
+
+    // --- Tracks -------------------------------------------------------------
+    info!("Resolving {} track(s)...", track_ids.len());
+    let raw_tracks = run_bounded(
+        &mut core,
+        track_ids
+            .iter()
+            .map(|id| Track::get(&session, *id).then(|res| Ok::<_, ()>(res)))
+            .collect(),
+        jobs,
+    )
+    .unwrap();
+
+    // Swap unavailable tracks for an available alternative. This depends on the
+    // track's own alternative list, so it is handled per id rather than batched.
+    let mut tracks: Vec<Track> = Vec::new();
+    for (id, res) in track_ids.iter().zip(raw_tracks) {
+        let mut track = match res {
+            Ok(track) => track,
+            Err(_) => {
+                warn!("Could not get metadata for track {}", id.to_base62());
+                continue;
+            }
+        };
+        if !track.available {
+            let fmtid = id.to_base62();
+            warn!("Track {} is not available, finding alternative...", fmtid);
+            let alt_track = track
+                .alternatives
+                .iter()
+                .map(|id| {
+                    core.run(Track::get(&session, *id))
+                        .expect("Cannot get track metadata")
+                })
+                .find(|alt_track| alt_track.available);
+            track = match alt_track {
+                Some(x) => {
+                    warn!("Found track alternative {} -> {}", fmtid, x.id.to_base62());
+                    x
                 }
+                None => panic!("Could not find alternative for track {}", fmtid),
+            };
+        }
+        tracks.push(track);
+    }
+
+    // Batch the album, artist and album-artist metadata lookups, each bounded
+    // by `--jobs` so a large playlist doesn't flood the reactor at once.
+    let albums = run_bounded(
+        &mut core,
+        tracks
+            .iter()
+            .map(|t| Album::get(&session, t.album))
+            .collect(),
+        jobs,
+    )
+    .expect("Cannot get album metadata");
+    let artist_lists: Vec<Vec<String>> = run_bounded(
+        &mut core,
+        tracks
+            .iter()
+            .map(|t| {
+                join_all(
+                    t.artists
+                        .iter()
+                        .map(|a| Artist::get(&session, *a))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect(),
+        jobs,
+    )
+    .expect("Cannot get artist metadata")
+    .into_iter()
+    .map(|artists| artists.into_iter().map(|a| a.name).collect())
+    .collect();
+    let album_artist_ids: Vec<(usize, SpotifyId)> = albums
+        .iter()
+        .enumerate()
+        .filter_map(|(i, al)| al.artists.first().map(|a| (i, *a)))
+        .collect();
+    let mut album_artists: Vec<String> = vec![String::new(); albums.len()];
+    let album_artist_names = run_bounded(
+        &mut core,
+        album_artist_ids
+            .iter()
+            .map(|(_, a)| Artist::get(&session, *a))
+            .collect(),
+        jobs,
+    )
+    .expect("Cannot get album artist metadata");
+    for ((index, _), artist) in album_artist_ids.iter().zip(album_artist_names) {
+        album_artists[*index] = artist.name;
+    }
+
+    // First pass (local only): resolve the output path for every track and skip
+    // the ones already on disk *before* opening any streams, so existing files
+    // cost no audio-key or stream-open round-trips.
+    struct PreparedTrack {
+        track: Track,
+        album: Album,
+        artists_strs: Vec<String>,
+        album_artist: String,
+        release_date: String,
+        file_format: FileFormat,
+        file_id: FileId,
+        fname: String,
+    }
+    let mut prepared: Vec<PreparedTrack> = Vec::new();
+    for (((track, album), artists_strs), album_artist) in tracks
+        .into_iter()
+        .zip(albums)
+        .zip(artist_lists)
+        .zip(album_artists)
+    {
+        let album_artist = if album_artist.is_empty() {
+            artists_strs.join(", ")
+        } else {
+            album_artist
+        };
+        let release_date = album.date.clone();
+        let (file_format, file_id) = {
+            let (format, file_id) = get_usable_file_id(&track.files, quality.formats());
+            (format, *file_id)
+        };
+
+        let mut fields = HashMap::new();
+        fields.insert("artist", artists_strs.join(", "));
+        fields.insert("title", track.name.clone());
+        fields.insert("album", album.name.clone());
+        fields.insert("album_artist", album_artist.clone());
+        fields.insert("track_number", track.number.to_string());
+        fields.insert("disc_number", track.disc_number.to_string());
+        fields.insert("year", release_date.chars().take(4).collect::<String>());
+        let expanded =
+            expand_template(template.as_deref().unwrap_or("{artist} - {title}"), &fields);
+        let path = build_output_path(&output_dir, &expanded, format_extension(file_format));
+        if path.exists() {
+            info!("File {} already exists.", path.display());
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("Cannot create output directory");
+        }
+        let fname = path.to_string_lossy().into_owned();
+
+        prepared.push(PreparedTrack {
+            track,
+            album,
+            artists_strs,
+            album_artist,
+            release_date,
+            file_format,
+            file_id,
+            fname,
+        });
+    }
+
+    // Second pass: request audio keys and open streams for the survivors, each
+    // batch bounded by `--jobs`.
+    let keys = run_bounded(
+        &mut core,
+        prepared
+            .iter()
+            .map(|p| session.audio_key().request(p.track.id, p.file_id))
+            .collect(),
+        jobs,
+    )
+    .expect("Cannot get audio key");
+    let encrypted_files = run_bounded(
+        &mut core,
+        prepared
+            .iter()
+            .map(|p| {
+                AudioFile::open(
+                    &session,
+                    p.file_id,
+                    format_bitrate(p.file_format) as usize,
+                    true,
+                )
+            })
+            .collect(),
+        jobs,
+    )
+    .unwrap();
+
+    // Assemble the track jobs from the batched results (local work only).
+    for (prep, (key, encrypted_file)) in
+        prepared.into_iter().zip(keys.into_iter().zip(encrypted_files))
+    {
+        let PreparedTrack {
+            track,
+            album,
+            artists_strs,
+            album_artist,
+            release_date,
+            file_format,
+            file_id: _,
+            fname,
+        } = prep;
+        let size = encrypted_file.get_stream_loader_controller().len();
+
+        let finish = if let Some(program) = &helper {
+            let mut helper_args =
+                vec![track.id.to_base62(), track.name.clone(), album.name.clone()];
+            helper_args.extend(artists_strs.iter().cloned());
+            Finish::Helper {
+                program: program.clone(),
+                args: helper_args,
+            }
+        } else {
+            let tags = if no_tag {
+                None
+            } else {
+                Some(TrackTags {
+                    title: track.name.clone(),
+                    artists: artists_strs.clone(),
+                    album: album.name.clone(),
+                    album_artist,
+                    track_number: track.number.max(0) as u32,
+                    disc_number: track.disc_number.max(0) as u32,
+                    date: release_date.clone(),
+                    cover: album.covers.first().copied(),
+                })
+            };
+            Finish::Write {
+                fname: fname.clone(),
+                tags,
             }
+        };
 
-            Episode => {
-                let fmtid = id.to_base62();
-                info!("Getting episode {}...", fmtid);
-                if let Ok(episode) = core.run(Episode::get(&session, id)) {
-                    if !episode.available {
-                        warn!("Episode {} is not available.", fmtid);
-                    }
-                    let show = core
-                        .run(Show::get(&session, episode.show))
-                        .expect("Cannot get show");
-                    debug!(
-                        "File formats: {}",
-                        episode
-                            .files
-                            .keys()
-                            .map(|filetype| format!("{:?}", filetype))
-                            .collect::<Vec<_>>()
-                            .join(" ")
-                    );
-                    let file_id = get_usable_file_id(&episode.files);
-                    let key = core
-                        .run(session.audio_key().request(episode.id, *file_id))
-                        .expect("Cannot get audio key");
-                    let mut encrypted_file = core
-                        .run(AudioFile::open(&session, *file_id, 320, true))
-                        .unwrap();
-                    let mut buffer = Vec::new();
-                    let mut read_all: Result<usize> = Ok(0);
-                    let fname = format!("{} - {}.ogg", show.publisher, episode.name);
-                    if Path::new(&fname).exists() {
-                        info!("File {} already exists.", fname);
-                    } else {
-                        let fetched = AtomicBool::new(false);
-                        threadpool.scoped(|scope| {
-                            scope.execute(|| {
-                                read_all = encrypted_file.read_to_end(&mut buffer);
-                                fetched.store(true, Ordering::Release);
-                            });
-                            while !fetched.load(Ordering::Acquire) {
-                                core.turn(Some(Duration::from_millis(100)));
-                            }
-                        });
-                        read_all.expect("Cannot read file stream");
-                        let mut decrypted_buffer = Vec::new();
-                        AudioDecrypt::new(key, &buffer[..])
-                            .read_to_end(&mut decrypted_buffer)
-                            .expect("Cannot decrypt stream");
-                        if args.len() == 3 {
-                            if Path::new(&fname).exists() {
-                                info!("File {} already exists.", fname);
-                            } else {
-                                std::fs::write(&fname, &decrypted_buffer[0xa7..])
-                                    .expect("Cannot write decrypted episode");
-                                info!("Filename: {}", fname);
-                            }
-                        } else {
-                            let mut cmd = Command::new(args[3].to_owned());
-                            cmd.stdin(Stdio::piped());
-                            cmd.arg(id.to_base62())
-                                .arg(episode.name)
-                                .arg(show.name)
-                                .arg(show.publisher);
-                            let mut child = cmd.spawn().expect("Could not run helper program");
-                            let pipe = child.stdin.as_mut().expect("Could not open helper stdin");
-                            pipe.write_all(&decrypted_buffer[0xa7..])
-                                .expect("Failed to write to stdin");
-                            assert!(
-                                child
-                                    .wait()
-                                    .expect("Out of ideas for error messages")
-                                    .success(),
-                                "Helper script returned an error"
-                            );
-                        }
-                    }
+        job_list.push(Job {
+            label: fname,
+            encrypted_file,
+            key,
+            size,
+            header_offset: if format_extension(file_format) == "ogg" {
+                0xa7
+            } else {
+                0
+            },
+            finish,
+        });
+    }
+
+    // --- Episodes -----------------------------------------------------------
+    info!("Resolving {} episode(s)...", episode_ids.len());
+    let raw_episodes = run_bounded(
+        &mut core,
+        episode_ids
+            .iter()
+            .map(|id| Episode::get(&session, *id).then(|res| Ok::<_, ()>(res)))
+            .collect(),
+        jobs,
+    )
+    .unwrap();
+    let mut episodes: Vec<Episode> = Vec::new();
+    for (id, res) in episode_ids.iter().zip(raw_episodes) {
+        match res {
+            Ok(episode) => {
+                if !episode.available {
+                    warn!("Episode {} is not available.", id.to_base62());
                 }
+                episodes.push(episode);
+            }
+            Err(_) => warn!("Could not get metadata for episode {}", id.to_base62()),
+        }
+    }
+    let shows = run_bounded(
+        &mut core,
+        episodes
+            .iter()
+            .map(|e| Show::get(&session, e.show))
+            .collect(),
+        jobs,
+    )
+    .expect("Cannot get show");
+
+    // First pass (local only): resolve paths and skip existing files before
+    // opening any streams, mirroring the track handling above.
+    struct PreparedEpisode {
+        episode: Episode,
+        show: Show,
+        file_format: FileFormat,
+        file_id: FileId,
+        fname: String,
+    }
+    let mut ep_prepared: Vec<PreparedEpisode> = Vec::new();
+    for (episode, show) in episodes.into_iter().zip(shows) {
+        let (file_format, file_id) = {
+            let (format, file_id) = get_usable_file_id(&episode.files, quality.formats());
+            (format, *file_id)
+        };
+
+        let mut fields = HashMap::new();
+        fields.insert("show", show.name.clone());
+        fields.insert("publisher", show.publisher.clone());
+        fields.insert("episode", episode.name.clone());
+        fields.insert("title", episode.name.clone());
+        let expanded = expand_template(
+            template.as_deref().unwrap_or("{publisher} - {episode}"),
+            &fields,
+        );
+        let path = build_output_path(&output_dir, &expanded, format_extension(file_format));
+        if path.exists() {
+            info!("File {} already exists.", path.display());
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("Cannot create output directory");
+        }
+        let fname = path.to_string_lossy().into_owned();
+
+        ep_prepared.push(PreparedEpisode {
+            episode,
+            show,
+            file_format,
+            file_id,
+            fname,
+        });
+    }
+
+    // Second pass: request audio keys and open streams for the survivors, each
+    // batch bounded by `--jobs`.
+    let ep_keys = run_bounded(
+        &mut core,
+        ep_prepared
+            .iter()
+            .map(|p| session.audio_key().request(p.episode.id, p.file_id))
+            .collect(),
+        jobs,
+    )
+    .expect("Cannot get audio key");
+    let ep_files = run_bounded(
+        &mut core,
+        ep_prepared
+            .iter()
+            .map(|p| {
+                AudioFile::open(
+                    &session,
+                    p.file_id,
+                    format_bitrate(p.file_format) as usize,
+                    true,
+                )
+            })
+            .collect(),
+        jobs,
+    )
+    .unwrap();
+
+    for (prep, (key, encrypted_file)) in
+        ep_prepared.into_iter().zip(ep_keys.into_iter().zip(ep_files))
+    {
+        let PreparedEpisode {
+            episode,
+            show,
+            file_format,
+            file_id: _,
+            fname,
+        } = prep;
+        let size = encrypted_file.get_stream_loader_controller().len();
+
+        let finish = if let Some(program) = &helper {
+            Finish::Helper {
+                program: program.clone(),
+                args: vec![
+                    episode.id.to_base62(),
+                    episode.name.clone(),
+                    show.name,
+                    show.publisher,
+                ],
+            }
+        } else {
+            Finish::Write {
+                fname: fname.clone(),
+                tags: None,
             }
+        };
+
+        job_list.push(Job {
+            label: fname,
+            encrypted_file,
+            key,
+            size,
+            header_offset: if format_extension(file_format) == "ogg" {
+                0xa7
+            } else {
+                0
+            },
+            finish,
+        });
+    }
+
+    if job_list.is_empty() {
+        return;
+    }
+
+    // Stream every job concurrently across the worker pool, rendering one
+    // progress bar per active download plus an overall completion bar. The main
+    // thread keeps pumping the reactor so the worker reads make progress.
+    let total = job_list.len();
+    let progress = MultiProgress::new();
+    let track_style = ProgressStyle::with_template("{msg:40} [{bar:30}] {bytes}/{total_bytes}")
+        .unwrap()
+        .progress_chars("=>-");
+    let overall_style = ProgressStyle::with_template("{msg:40} [{bar:30}] {pos}/{len}")
+        .unwrap()
+        .progress_chars("=>-");
+    let overall = progress.add(ProgressBar::new(total as u64));
+    overall.set_style(overall_style);
+    overall.set_message("Overall");
+
+    let remaining = AtomicUsize::new(total);
+    let overall_ref = &overall;
+    let remaining_ref = &remaining;
+
+    threadpool.scoped(|scope| {
+        for job in job_list {
+            let bar = progress.add(ProgressBar::new(job.size as u64));
+            bar.set_style(track_style.clone());
+            bar.set_message(job.label.clone());
+            scope.execute(move || {
+                run_job(job, bar);
+                overall_ref.inc(1);
+                remaining_ref.fetch_sub(1, Ordering::Release);
+            });
+        }
+        while remaining_ref.load(Ordering::Acquire) > 0 {
+            core.turn(Some(Duration::from_millis(100)));
         }
+    });
+
+    overall.finish_with_message("Done");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&'static str, &str)]) -> HashMap<&'static str, String> {
+        pairs.iter().map(|(k, v)| (*k, v.to_string())).collect()
+    }
+
+    #[test]
+    fn expand_template_substitutes_and_pads() {
+        let fields = fields(&[("artist", "Pink Floyd"), ("title", "Time"), ("track_number", "3")]);
+        assert_eq!(
+            expand_template("{track_number:02} - {title}", &fields),
+            "03 - Time"
+        );
+        assert_eq!(expand_template("{artist}/{title}", &fields), "Pink Floyd/Time");
+    }
+
+    #[test]
+    fn expand_template_missing_field_is_empty() {
+        let fields = fields(&[("title", "Time")]);
+        assert_eq!(expand_template("{artist} - {title}", &fields), " - Time");
+    }
+
+    #[test]
+    fn expand_template_sanitises_slashes_in_values() {
+        let fields = fields(&[("artist", "AC/DC"), ("title", "T.N.T")]);
+        // The slash in the artist must not survive as a path separator.
+        assert_eq!(expand_template("{artist} - {title}", &fields), "ACDC - T.N.T");
+    }
+
+    #[test]
+    fn build_output_path_splits_on_literal_separators() {
+        let path = build_output_path(Path::new("/music"), "Pink Floyd/Time", "ogg");
+        assert_eq!(path, PathBuf::from("/music/Pink Floyd/Time.ogg"));
+    }
+
+    #[test]
+    fn build_output_path_keeps_dots_in_title() {
+        let path = build_output_path(Path::new("."), "Time.Is.Up", "mp3");
+        assert_eq!(path, PathBuf::from("./Time.Is.Up.mp3"));
+    }
+
+    #[test]
+    fn strip_query_drops_share_token_and_fragment() {
+        assert_eq!(strip_query("https://open.spotify.com/track/abc?si=xyz"), "https://open.spotify.com/track/abc");
+        assert_eq!(strip_query("track/abc#frag"), "track/abc");
+        assert_eq!(strip_query("track/abc"), "track/abc");
+    }
+
+    #[test]
+    fn format_extension_matches_codec() {
+        assert_eq!(format_extension(FileFormat::MP3_320), "mp3");
+        assert_eq!(format_extension(FileFormat::MP3_96), "mp3");
+        assert_eq!(format_extension(FileFormat::OGG_VORBIS_320), "ogg");
+        assert_eq!(format_extension(FileFormat::OGG_VORBIS_96), "ogg");
+    }
+
+    #[test]
+    fn format_bitrate_matches_format() {
+        assert_eq!(format_bitrate(FileFormat::OGG_VORBIS_320), 320);
+        assert_eq!(format_bitrate(FileFormat::MP3_256), 256);
+        assert_eq!(format_bitrate(FileFormat::OGG_VORBIS_160), 160);
+        assert_eq!(format_bitrate(FileFormat::OGG_VORBIS_96), 96);
     }
 }